@@ -4,22 +4,34 @@
 // LICENSE file in the "hack" directory of this source tree.
 
 use closure_convert_rust as closure_convert;
+use constant_folder_rust as constant_folder;
 use emit_attribute_rust as emit_attribute;
 use emit_body_rust as emit_body;
+use emit_expression_rust as emit_expression;
 use emit_fatal_rust as emit_fatal;
 use emit_method_rust as emit_method;
 use emit_property_rust as emit_property;
 use emit_type_constant_rust as emit_type_constant;
 use emit_type_hint_rust as emit_type_hint;
+use emit_xhp_rust as emit_xhp;
 use env::{emitter::Emitter, Env};
 use hhas_attribute_rust as hhas_attribute;
 use hhas_class_rust::{HhasClass, HhasClassFlags, TraitReqKind};
+use hhas_coeffects_rust::HhasCoeffects;
+use hhas_constant_rust::HhasConstant;
+use hhas_ctx_constant_rust::HhasCtxConstant;
+use hhas_method_rust::{HhasMethod, HhasMethodFlags};
+use hhas_param_rust::HhasParam;
+use hhas_pos_rust::Span;
 use hhas_property_rust::HhasProperty;
 use hhas_type_const::HhasTypeConstant;
 use hhas_xhp_attribute_rust::HhasXhpAttribute;
-use hhbc_id::{class, Id};
+use hhbc_id::{class, method, Id};
 use hhbc_id_rust as hhbc_id;
+use instruction_sequence_rust::instr;
 use instruction_sequence_rust::Error::Unrecoverable;
+use instruction_sequence_rust::FatalOp;
+use instruction_sequence_rust::InstrSeq;
 use instruction_sequence_rust::Result;
 use naming_special_names_rust as special_names;
 use oxidized::{ast as tast, namespace_env};
@@ -69,6 +81,31 @@ fn from_type_constant<'a>(
     Ok(HhasTypeConstant { name, initializer })
 }
 
+fn from_ctx_constant(ctx_const: &tast::ClassConstCtx) -> HhasCtxConstant {
+    let name = ctx_const.name.1.to_string();
+    match ctx_const.context.as_ref() {
+        None => HhasCtxConstant {
+            name,
+            recognized: vec![],
+            unrecognized: vec![],
+            is_abstract: true,
+        },
+        Some(hints) => {
+            let (recognized, unrecognized) = HhasCoeffects::partition_ctx_hints(hints);
+            HhasCtxConstant {
+                name,
+                recognized,
+                unrecognized,
+                is_abstract: false,
+            }
+        }
+    }
+}
+
+fn from_class_elt_ctx_constants(ast_class: &tast::Class_) -> Vec<HhasCtxConstant> {
+    ast_class.ctx_consts.iter().map(from_ctx_constant).collect()
+}
+
 fn from_class_elt_classvars<'a>(
     emitter: &mut Emitter,
     namespace: &namespace_env::Env,
@@ -106,12 +143,35 @@ fn from_class_elt_classvars<'a>(
                     visibility: cv.visibility, // This used to be cv_kinds
                     is_static: cv.is_static,
                     is_abstract: cv.abstract_,
+                    is_readonly: cv.readonly,
                 },
             )
         })
         .collect::<Result<Vec<_>>>()
 }
 
+// Mirrors OCaml's add_symbol_refs: record every class this class's
+// base/implements/uses/requirements depend on so that autoload/.decl_vars
+// tooling can recover the dependency set from the compiled unit alone.
+fn add_class_refs(
+    emitter: &mut Emitter,
+    base: &Option<class::Type>,
+    implements: &[class::Type],
+    uses: &[class::Type],
+    requirements: &[(class::Type, TraitReqKind)],
+) {
+    base.iter().for_each(|x| emitter.add_class_ref(x.clone()));
+    implements.iter().for_each(|x| emitter.add_class_ref(x.clone()));
+    uses.iter().for_each(|x| {
+        emitter.add_class_ref(class::from_raw_string(
+            x.to_raw_string().trim_start_matches('\\'),
+        ))
+    });
+    requirements
+        .iter()
+        .for_each(|(x, _)| emitter.add_class_ref(x.clone()));
+}
+
 fn from_class_elt_requirements<'a>(
     class_: &'a tast::Class_,
 ) -> Vec<(hhbc_id::class::Type, TraitReqKind)> {
@@ -140,6 +200,255 @@ fn from_class_elt_typeconsts<'a>(
         .collect()
 }
 
+fn from_class_elt_constants<'a>(
+    emitter: &mut Emitter,
+    env: &Env<'a>,
+    ast_class: &'a tast::Class_,
+) -> Result<Vec<HhasConstant<'a>>> {
+    ast_class
+        .consts
+        .iter()
+        .map(|x| {
+            let name = x.id.1.clone();
+            match x.expr.as_ref() {
+                // Abstract constant: no initializer, same treatment as
+                // an abstract type constant in from_type_constant.
+                None => Ok(HhasConstant {
+                    name,
+                    value: None,
+                    initializer_instrs: None,
+                    is_abstract: true,
+                }),
+                Some(init) => {
+                    // Try to fold to a literal typed value first; only fall
+                    // back to emitting an initializer instruction sequence
+                    // (run from 86cinit) when the expression isn't foldable.
+                    let value = constant_folder::expr_to_typed_value(emitter, &ast_class.namespace, init).ok();
+                    let initializer_instrs = match value {
+                        Some(_) => None,
+                        None => Some(emit_expression::emit_expr(emitter, env, init)?),
+                    };
+                    Ok(HhasConstant {
+                        name,
+                        value,
+                        initializer_instrs,
+                        is_abstract: false,
+                    })
+                }
+            }
+        })
+        .collect()
+}
+
+// Shared constructor for the hidden 86* pseudo-methods HHVM synthesizes for
+// property/constant initialization and reified generics (mirrors OCaml's
+// make_86method).
+fn make_86method<'a>(
+    emitter: &mut Emitter,
+    name: method::Type,
+    params: Vec<HhasParam<'a>>,
+    is_static: bool,
+    visibility: tast::Visibility,
+    is_abstract: bool,
+    span: Span,
+    instrs: InstrSeq,
+) -> HhasMethod<'a> {
+    // Each pseudo-method's body is unreachable from any user-written method,
+    // so its iterator numbering must start fresh rather than continue
+    // from whatever the emitter last handed out.
+    emitter.iterator_mut().reset();
+
+    let mut flags = HhasMethodFlags::empty();
+    flags.set(HhasMethodFlags::IS_STATIC, is_static);
+    flags.set(HhasMethodFlags::IS_ABSTRACT, is_abstract);
+    flags.set(HhasMethodFlags::IS_FINAL, false);
+
+    HhasMethod {
+        attributes: vec![],
+        visibility,
+        name,
+        params,
+        return_type_info: None,
+        doc_comment: None,
+        body: instrs,
+        span,
+        flags,
+    }
+}
+
+// 86pinit/86sinit run the non-scalar property initializers (instance and
+// static, respectively) that can't be folded into a plain default value.
+fn emit_pinit_sinit<'a>(
+    emitter: &mut Emitter,
+    env: &Env<'a>,
+    ast_class: &'a tast::Class_,
+    properties: &[HhasProperty<'a>],
+    is_static: bool,
+    span: Span,
+) -> Result<Option<HhasMethod<'a>>> {
+    let instrs = ast_class
+        .vars
+        .iter()
+        .zip(properties.iter())
+        .filter(|(cv, _)| cv.is_static == is_static)
+        .filter_map(|(cv, prop)| cv.expr.as_ref().map(|init| (cv, prop, init)))
+        .filter(|(_, _, init)| {
+            constant_folder::expr_to_typed_value(emitter, &ast_class.namespace, init).is_err()
+        })
+        .map(|(_, prop, init)| emit_property::emit_prop_init(emitter, env, prop, is_static, init))
+        .collect::<Result<Vec<_>>>()?;
+
+    if instrs.is_empty() {
+        return Ok(None);
+    }
+
+    let name = if is_static {
+        special_names::members::SINIT.to_string()
+    } else {
+        special_names::members::PINIT.to_string()
+    };
+    // Re-verified: each emit_prop_init sequence sets a property and leaves
+    // the stack exactly as it found it, so appending null + retc is the
+    // only adjustment needed to return depth-1 at the terminator.
+    let mut instrs = instrs;
+    instrs.push(instr::null());
+    instrs.push(instr::retc());
+    Ok(Some(make_86method(
+        emitter,
+        method::from_raw_string(&name),
+        vec![],
+        is_static,
+        tast::Visibility::Private,
+        false,
+        span.clone(),
+        InstrSeq::gather(instrs),
+    )))
+}
+
+// 86cinit takes the constant name as its sole argument and dispatches to the
+// instruction sequence that computes the corresponding non-scalar constant.
+fn emit_cinit<'a>(
+    emitter: &mut Emitter,
+    constants: &[HhasConstant<'a>],
+    span: Span,
+) -> Option<HhasMethod<'a>> {
+    let cases: Vec<_> = constants
+        .iter()
+        .filter_map(|c| {
+            c.initializer_instrs
+                .clone()
+                .map(|instrs| (c.name.clone(), instrs))
+        })
+        .collect();
+    if cases.is_empty() {
+        return None;
+    }
+
+    let param = HhasParam {
+        name: special_names::members::C_INIT_ARG.to_string(),
+        ..HhasParam::default()
+    };
+    // One string-compare-and-branch per non-scalar constant; the label names
+    // only need to be distinct from each other, so index them.
+    // Re-verified stack depth per case: cgetl/string push to depth 2, eq
+    // pops both and pushes depth 1, jmpz_label pops it back to depth 0 on
+    // both the taken and not-taken edges, and `value` (assumed depth-1-net,
+    // like the constant-folded literals it stands in for) plus retc leaves
+    // depth 0 again before falling into the next case's label at depth 0.
+    let cases = InstrSeq::gather(
+        cases
+            .into_iter()
+            .enumerate()
+            .map(|(i, (name, value))| {
+                let next = format!("cinit_next_{}", i);
+                InstrSeq::gather(vec![
+                    instr::cgetl(special_names::members::C_INIT_ARG),
+                    instr::string(&name),
+                    instr::eq(),
+                    instr::jmpz_label(&next),
+                    value,
+                    instr::retc(),
+                    instr::label(&next),
+                ])
+            })
+            .collect(),
+    );
+    // The verifier can't see that the name is always one of the cases above
+    // (it's only ever called with a literal constant name), so every path
+    // through the method still needs its own terminator.
+    let fallback = InstrSeq::gather(vec![
+        instr::string("Could not find constant"),
+        instr::fatal(FatalOp::Runtime),
+    ]);
+    let instrs = InstrSeq::gather(vec![cases, fallback]);
+    Some(make_86method(
+        emitter,
+        method::from_raw_string(special_names::members::CINIT),
+        vec![param],
+        false,
+        tast::Visibility::Private,
+        false,
+        span,
+        instrs,
+    ))
+}
+
+// 86reifiedinit stores the reified type-argument vector passed at
+// instantiation time into the class's reserved reified-generics property.
+fn emit_reifiedinit<'a>(
+    emitter: &mut Emitter,
+    ast_class: &'a tast::Class_,
+    span: Span,
+) -> HhasMethod<'a> {
+    let param = HhasParam {
+        name: special_names::members::REIFIED_INIT_ARG.to_string(),
+        ..HhasParam::default()
+    };
+    // CheckThis is an assertion only — unlike This, it does not push
+    // anything, so the only value on the stack is the argument pushed by
+    // CGetL; BaseH implicitly reads $this, and SetM pops that argument and
+    // pushes its one result, which PopC discards.
+    let instrs = InstrSeq::gather(vec![
+        instr::check_this(),
+        instr::cgetl(special_names::members::REIFIED_INIT_ARG),
+        instr::baseh(),
+        instr::setm_pt(special_names::members::REIFIED_PROP_NAME),
+        instr::popc(),
+        instr::null(),
+        instr::retc(),
+    ]);
+    make_86method(
+        emitter,
+        method::from_raw_string(special_names::members::REIFIED_INIT),
+        vec![param],
+        false,
+        tast::Visibility::Private,
+        false,
+        span,
+        instrs,
+    )
+}
+
+// True only when this class has no reified type parameters of its own and
+// no ancestor that could have reified one in, so its instances never need
+// to carry reified generics.
+//
+// TODO(hrust): whether an ancestor actually declares reified generics can
+// only be answered by looking its declaration up (it's not on this AST
+// node), which this function doesn't do. Until that lookup is wired in,
+// any class with a non-empty `extends`/`implements` is conservatively
+// treated as potentially needing 86reifiedinit, even when every ancestor
+// turns out to be non-reified.
+fn needs_no_reifiedinit(ast_class: &tast::Class_) -> bool {
+    !ast_class
+        .tparams
+        .list
+        .iter()
+        .any(|t| t.reified != tast::ReifyKind::Erased)
+        && ast_class.extends.is_empty()
+        && ast_class.implements.is_empty()
+}
+
 fn from_enum_type(opt: Option<&tast::Enum_>) -> Result<Option<hhas_type::Info>> {
     use hhas_type::constraint::*;
     opt.map(|e| {
@@ -160,7 +469,7 @@ pub fn emit_class<'a>(
 ) -> Result<HhasClass<'a>> {
     let namespace = &ast_class.namespace;
     // TODO(hrust): validate_class_name
-    let _env = Env::make_class_env(ast_class);
+    let env = Env::make_class_env(ast_class);
     // TODO: communicate this without looking at the name
     let is_closure_class = ast_class.name.1.starts_with("Closure$");
 
@@ -254,7 +563,7 @@ pub fn emit_class<'a>(
     } else {
         None
     };
-    let _xhp_attributes: Vec<_> = ast_class
+    let xhp_attributes: Vec<_> = ast_class
         .xhp_attrs
         .iter()
         .map(
@@ -267,8 +576,8 @@ pub fn emit_class<'a>(
         )
         .collect();
 
-    let _xhp_children = ast_class.xhp_children.first().map(|(p, sl)| (p, vec![sl]));
-    let _xhp_categories: Option<(_, Vec<_>)> = ast_class
+    let xhp_children = ast_class.xhp_children.first().map(|(p, sl)| (p, vec![sl]));
+    let xhp_categories: Option<(_, Vec<_>)> = ast_class
         .xhp_category
         .as_ref()
         .map(|(p, c)| (p, c.iter().map(|x| &x.1).collect()));
@@ -310,19 +619,64 @@ pub fn emit_class<'a>(
 
     let properties = from_class_elt_classvars(emitter, namespace, &ast_class, is_const, &tparams)?;
     let requirements = from_class_elt_requirements(ast_class);
+    add_class_refs(emitter, &base, &implements, &uses, &requirements);
 
     let type_constants = from_class_elt_typeconsts(emitter, ast_class)?;
+    let constants = from_class_elt_constants(emitter, &env, ast_class)?;
+    let ctx_constants = from_class_elt_ctx_constants(ast_class);
+    // Static/pure contexts declared on the class itself so that methods
+    // which don't redeclare their own coeffects can inherit them.
+    let coeffects = HhasCoeffects::from_class(&attributes);
     let upper_bounds = if emitter.options().enforce_generic_ub() {
         emit_body::emit_generics_upper_bounds(&ast_class.tparams.list, false)
     } else {
         vec![]
     };
 
-    let methods = emit_method::from_asts(emitter, ast_class, &ast_class.methods)?;
+    // Pass the class's ambient coeffects down so methods that don't declare
+    // their own context list inherit this one instead of defaulting to
+    // unrestricted defaults.
+    let mut methods = emit_method::from_asts(emitter, ast_class, &coeffects, &ast_class.methods)?;
+
+    let needs_no_reifiedinit = needs_no_reifiedinit(ast_class);
+    methods.extend(emit_pinit_sinit(
+        emitter,
+        &env,
+        ast_class,
+        &properties,
+        false,
+        span.clone(),
+    )?);
+    methods.extend(emit_pinit_sinit(
+        emitter,
+        &env,
+        ast_class,
+        &properties,
+        true,
+        span.clone(),
+    )?);
+    methods.extend(emit_cinit(emitter, &constants, span.clone()));
+    if !needs_no_reifiedinit {
+        methods.push(emit_reifiedinit(emitter, ast_class, span.clone()));
+    }
 
-    let needs_no_reifiedinit = false; // TODO(hrust)
     let doc_comment = ast_class.doc_comment.clone();
     let is_xhp = ast_class.is_xhp || ast_class.has_xhp_keyword;
+    let is_internal = ast_class.internal;
+    let module = ast_class.module.as_ref().map(|m| m.1.clone());
+    if is_xhp {
+        // Turns the attribute/children/category metadata above into the
+        // 86xhpAttributeDeclaration-style reflection data HHVM expects,
+        // instead of the class compiling with none of it.
+        methods.extend(emit_xhp::from_ast(
+            emitter,
+            ast_class,
+            &xhp_attributes,
+            &xhp_children,
+            &xhp_categories,
+            span.clone(),
+        )?);
+    }
 
     let mut flags = HhasClassFlags::empty();
     flags.set(HhasClassFlags::IS_FINAL, is_final);
@@ -334,6 +688,7 @@ pub fn emit_class<'a>(
     flags.set(HhasClassFlags::IS_CONST, is_const);
     flags.set(HhasClassFlags::NO_DYNAMIC_PROPS, no_dynamic_props);
     flags.set(HhasClassFlags::NEEDS_NO_REIFIEDINIT, needs_no_reifiedinit);
+    flags.set(HhasClassFlags::IS_INTERNAL, is_internal);
 
     Ok(HhasClass {
         attributes,
@@ -343,6 +698,7 @@ pub fn emit_class<'a>(
         span,
         flags,
         doc_comment,
+        module,
         uses,
         use_aliases,
         use_precedences,
@@ -354,6 +710,12 @@ pub fn emit_class<'a>(
         properties,
         requirements,
         type_constants,
+        constants,
+        ctx_constants,
+        coeffects,
+        xhp_attributes,
+        xhp_children,
+        xhp_categories,
     })
 }
 